@@ -0,0 +1,230 @@
+//! The concrete filesystem operations a deploy/undeploy performs, derived by diffing desired vs.
+//! existing [`FileState`](crate::file_state::FileState).
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::args::Options;
+use crate::config::{Cache, LineEnding, Variables};
+use crate::file_state::{SymlinkDescription, TemplateDescription};
+use crate::filesystem::{Filesystem, SymlinkComparison, TemplateComparison};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    CreateSymlink(SymlinkDescription),
+    UpdateSymlink(SymlinkDescription),
+    DeleteSymlink { source: PathBuf, target: PathBuf },
+    CreateTemplate(TemplateDescription),
+    UpdateTemplate(TemplateDescription),
+    DeleteTemplate {
+        source: PathBuf,
+        cache: PathBuf,
+        target: PathBuf,
+    },
+}
+
+impl Action {
+    /// Performs this action. Returns `Ok(true)` if it was applied, `Ok(false)` if it was skipped
+    /// because the target was unexpectedly changed and `--force` wasn't passed.
+    pub fn run(
+        &self,
+        fs: &mut dyn Filesystem,
+        opt: &Options,
+        handlebars: &handlebars::Handlebars,
+        variables: &Variables,
+    ) -> Result<bool> {
+        match self {
+            Action::CreateSymlink(desc) | Action::UpdateSymlink(desc) => {
+                sync_symlink(desc, fs, opt)
+            }
+            Action::DeleteSymlink { target, .. } => delete_path(fs, target),
+            Action::CreateTemplate(desc) | Action::UpdateTemplate(desc) => {
+                sync_template(desc, fs, opt, handlebars, variables)
+            }
+            Action::DeleteTemplate { target, .. } => delete_path(fs, target),
+        }
+    }
+
+    /// Updates `cache` to reflect that this action was applied, so the next deploy's "existing"
+    /// file state matches reality.
+    pub fn affect_cache(&self, cache: &mut Cache) {
+        match self {
+            Action::CreateSymlink(desc) | Action::UpdateSymlink(desc) => {
+                cache
+                    .symlinks
+                    .insert(desc.source.clone(), desc.target.target.clone());
+            }
+            Action::DeleteSymlink { source, target } => {
+                // Only drop the entry if it still records this target: a create of the same
+                // source at a new target may already have been applied.
+                if cache.symlinks.get(source) == Some(target) {
+                    cache.symlinks.remove(source);
+                }
+            }
+            Action::CreateTemplate(desc) | Action::UpdateTemplate(desc) => {
+                cache
+                    .templates
+                    .insert(desc.source.clone(), desc.target.target.clone());
+            }
+            Action::DeleteTemplate { source, target, .. } => {
+                if cache.templates.get(source) == Some(target) {
+                    cache.templates.remove(source);
+                }
+            }
+        }
+    }
+}
+
+fn parent_or_empty(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new(""))
+}
+
+fn sync_symlink(desc: &SymlinkDescription, fs: &mut dyn Filesystem, opt: &Options) -> Result<bool> {
+    let comparison = fs.compare_symlink(&desc.source, &desc.target.target)?;
+    match comparison {
+        SymlinkComparison::Identical => return Ok(true),
+        SymlinkComparison::Changed if !opt.force => {
+            warn!(
+                "{:?} already exists and doesn't match the desired symlink; skipping (use --force to overwrite)",
+                desc.target.target
+            );
+            return Ok(false);
+        }
+        SymlinkComparison::Changed | SymlinkComparison::OnlySourceExists => {}
+    }
+
+    fs.create_dir_all(
+        parent_or_empty(&desc.target.target),
+        desc.target.owner.clone(),
+    )?;
+    fs.make_symlink(&desc.target.target, &desc.source, desc.target.owner.clone())?;
+    Ok(true)
+}
+
+fn sync_template(
+    desc: &TemplateDescription,
+    fs: &mut dyn Filesystem,
+    opt: &Options,
+    handlebars: &handlebars::Handlebars,
+    variables: &Variables,
+) -> Result<bool> {
+    let comparison = fs.compare_template(&desc.target.target, &desc.cache)?;
+    if let TemplateComparison::Changed = comparison {
+        if !opt.force {
+            warn!(
+                "{:?} already exists and doesn't match the last deployed template; skipping (use --force to overwrite)",
+                desc.target.target
+            );
+            return Ok(false);
+        }
+    }
+
+    fs.create_dir_all(
+        parent_or_empty(&desc.target.target),
+        desc.target.owner.clone(),
+    )?;
+
+    let source_content = fs.read_to_string(&desc.source)?;
+    let rendered = handlebars
+        .render_template(&source_content, variables)
+        .with_context(|| format!("render template {:?}", desc.source))?;
+
+    // Only worth detecting the deployed target's line ending when it actually exists -- on a
+    // first deploy there's nothing yet to match, so fall back to the platform default.
+    let existing_target = match comparison {
+        TemplateComparison::BothMissing => None,
+        _ => fs.read_to_string(&desc.target.target).ok(),
+    };
+    let line_ending = desc
+        .target
+        .line_ending
+        .or_else(|| existing_target.as_deref().and_then(LineEnding::detect))
+        .unwrap_or(LineEnding::Native);
+    let rendered = line_ending.normalize(&rendered);
+
+    // The target and cache already match what was last deployed -- if re-rendering produced the
+    // same content, there's nothing to write or copy, so skip straight to a no-op success rather
+    // than needlessly rewriting an unchanged target on every deploy.
+    if let TemplateComparison::Identical = comparison {
+        if fs.read_to_string(&desc.cache).is_ok_and(|cached| cached == rendered) {
+            return Ok(true);
+        }
+    }
+
+    fs.create_dir_all(parent_or_empty(&desc.cache), None)?;
+    fs.write(&desc.cache, rendered)?;
+    fs.copy_file(&desc.cache, &desc.target.target, desc.target.owner.clone())?;
+    fs.copy_permissions(&desc.source, &desc.target.target, desc.target.owner.clone())?;
+
+    Ok(true)
+}
+
+fn delete_path(fs: &mut dyn Filesystem, target: &Path) -> Result<bool> {
+    fs.remove(target)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::TemplateTarget;
+    use crate::filesystem::MockFilesystem;
+    use mockall::predicate::*;
+
+    fn path_eq(expected: &str) -> impl Fn(&Path) -> bool {
+        let expected = PathBuf::from(expected);
+        move |actual| actual == expected
+    }
+
+    #[test]
+    fn sync_template_skips_writing_when_content_is_unchanged() {
+        let desc = TemplateDescription {
+            source: "in".into(),
+            target: TemplateTarget {
+                target: "out".into(),
+                owner: None,
+                append: None,
+                prepend: None,
+                line_ending: None,
+            },
+            cache: "cache".into(),
+        };
+
+        let mut fs = MockFilesystem::new();
+        let mut seq = mockall::Sequence::new();
+
+        fs.expect_compare_template()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(TemplateComparison::Identical));
+        fs.expect_create_dir_all()
+            .times(1)
+            .with(function(path_eq("")), eq(None))
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        fs.expect_read_to_string()
+            .times(1)
+            .with(function(path_eq("in")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("hello".into()));
+        fs.expect_read_to_string()
+            .times(1)
+            .with(function(path_eq("out")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("hello".into()));
+        fs.expect_read_to_string()
+            .times(1)
+            .with(function(path_eq("cache")))
+            .in_sequence(&mut seq)
+            .returning(|_| Ok("hello".into()));
+        // No `create_dir_all` for the cache parent, `write`, `copy_file` or `copy_permissions`
+        // expectations: since nothing changed, none of them should be called.
+
+        let options = Options::default();
+        let handlebars = handlebars::Handlebars::new();
+        let variables = Default::default();
+
+        let result = sync_template(&desc, &mut fs, &options, &handlebars, &variables).unwrap();
+        assert!(result);
+    }
+}
@@ -0,0 +1,567 @@
+//! Loading and merging of the user's configuration files (`dotter.toml` by default).
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub type Variables = BTreeMap<String, toml::Value>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SymbolicTarget {
+    pub target: PathBuf,
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TemplateTarget {
+    pub target: PathBuf,
+    pub owner: Option<String>,
+    pub append: Option<String>,
+    pub prepend: Option<String>,
+    /// Line ending the rendered template should be normalized to before writing. If unset, the
+    /// existing deployed target's dominant line ending is detected and kept, falling back to
+    /// the platform default when there is nothing deployed yet to detect from.
+    #[serde(default)]
+    pub line_ending: Option<LineEnding>,
+}
+
+/// The line ending a rendered template is normalized to, to avoid spurious whole-file diffs and
+/// cache mismatches when a template is authored with one convention but deployed under another.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    /// `\r\n` on Windows, `\n` everywhere else.
+    Native,
+}
+
+impl LineEnding {
+    #[cfg(windows)]
+    const NATIVE: LineEnding = LineEnding::Crlf;
+    #[cfg(not(windows))]
+    const NATIVE: LineEnding = LineEnding::Lf;
+
+    fn resolved(self) -> LineEnding {
+        match self {
+            LineEnding::Native => Self::NATIVE,
+            other => other,
+        }
+    }
+
+    /// Detects the dominant line ending already used in `content`: CRLF if `\r\n` occurs at
+    /// least as often as a bare `\n`, LF otherwise, or `None` if `content` has no line breaks
+    /// at all.
+    pub fn detect(content: &str) -> Option<LineEnding> {
+        let crlf = content.matches("\r\n").count();
+        let bare_lf = content.matches('\n').count() - crlf;
+        if crlf == 0 && bare_lf == 0 {
+            None
+        } else if crlf >= bare_lf {
+            Some(LineEnding::Crlf)
+        } else {
+            Some(LineEnding::Lf)
+        }
+    }
+
+    /// Rewrites every line ending in `content` to this one.
+    pub fn normalize(self, content: &str) -> String {
+        let lf = content.replace("\r\n", "\n");
+        match self.resolved() {
+            LineEnding::Lf => lf,
+            LineEnding::Crlf => lf.replace('\n', "\r\n"),
+            LineEnding::Native => unreachable!("resolved() never returns Native"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Package {
+    #[serde(default)]
+    pub symlinks: BTreeMap<String, SymbolicTarget>,
+    #[serde(default)]
+    pub templates: BTreeMap<String, TemplateTarget>,
+    /// If set, this package's sources live in a remote git repository rather than alongside the
+    /// dotfiles repo itself.
+    #[serde(default)]
+    pub source: Option<RemoteSource>,
+}
+
+/// A package source that should be fetched from a git repository instead of resolved as a
+/// local path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteSource {
+    pub git: String,
+    /// Branch, tag, or commit to check out. Defaults to the remote's default branch.
+    #[serde(default)]
+    pub rev: Option<String>,
+    /// Path within the checkout that symlink/template sources are resolved against.
+    #[serde(default)]
+    pub subdirectory: Option<PathBuf>,
+}
+
+/// The declared type of a [`VariableDefinition`], used to validate a user's answer when
+/// prompting interactively.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VariableType {
+    String,
+    Bool,
+    Enum { choices: Vec<String> },
+}
+
+/// A variable the config declares but doesn't necessarily set a value for, so that `dotter`
+/// can prompt the user for it on first deploy instead of failing template rendering.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariableDefinition {
+    #[serde(flatten)]
+    pub ty: VariableType,
+    #[serde(default)]
+    pub default: Option<toml::Value>,
+    #[serde(default)]
+    pub prompt: Option<String>,
+}
+
+impl VariableDefinition {
+    /// Parses and validates a line of user input against this definition's declared type.
+    pub fn parse(&self, input: &str) -> Result<toml::Value> {
+        match &self.ty {
+            VariableType::String => Ok(toml::Value::String(input.to_string())),
+            VariableType::Bool => match input.trim().to_lowercase().as_str() {
+                "y" | "yes" | "true" => Ok(toml::Value::Boolean(true)),
+                "n" | "no" | "false" => Ok(toml::Value::Boolean(false)),
+                _ => bail!("expected a yes/no value, got {:?}", input),
+            },
+            VariableType::Enum { choices } => {
+                if choices.iter().any(|choice| choice == input) {
+                    Ok(toml::Value::String(input.to_string()))
+                } else {
+                    bail!("expected one of {:?}, got {:?}", choices, input)
+                }
+            }
+        }
+    }
+
+    /// Validates that `value` (e.g. this definition's own `default`, which isn't typed through
+    /// [`parse`](Self::parse) since it's already a `toml::Value` rather than raw user input)
+    /// matches this definition's declared type, so a malformed `default` in the config is caught
+    /// up front instead of flowing silently into template rendering.
+    pub fn validate(&self, value: &toml::Value) -> Result<()> {
+        match &self.ty {
+            VariableType::String => {
+                if value.is_str() {
+                    Ok(())
+                } else {
+                    bail!("expected a string, got {:?}", value)
+                }
+            }
+            VariableType::Bool => {
+                if value.is_bool() {
+                    Ok(())
+                } else {
+                    bail!("expected a boolean, got {:?}", value)
+                }
+            }
+            VariableType::Enum { choices } => match value.as_str() {
+                Some(s) if choices.iter().any(|choice| choice == s) => Ok(()),
+                _ => bail!("expected one of {:?}, got {:?}", choices, value),
+            },
+        }
+    }
+}
+
+/// A configuration file as it is read off disk, before includes are resolved or unsets applied.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct RawConfiguration {
+    /// Other config files to merge in before this one, resolved relative to this file's
+    /// directory and applied in order -- later includes (and this file itself) win ties.
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    /// Dotted paths (e.g. `packages.foo`, `variables.bar`) to remove after includes are merged
+    /// in, so a layer can subtract something an included/global layer defined.
+    #[serde(default)]
+    unset: Vec<String>,
+    #[serde(default)]
+    packages: BTreeMap<String, Package>,
+    #[serde(default)]
+    variables: Variables,
+    #[serde(default)]
+    variable_definitions: BTreeMap<String, VariableDefinition>,
+}
+
+/// The fully merged configuration, after includes are resolved and unsets applied.
+#[derive(Debug, Clone, Default)]
+pub struct Configuration {
+    pub packages: BTreeMap<String, Package>,
+    pub variables: Variables,
+    pub variable_definitions: BTreeMap<String, VariableDefinition>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Cache {
+    pub symlinks: BTreeMap<PathBuf, PathBuf>,
+    pub templates: BTreeMap<PathBuf, PathBuf>,
+    /// Answers collected by prompting for `variable_definitions`, reused on later deploys
+    /// unless `--reconfigure` is passed.
+    #[serde(default)]
+    pub variable_answers: BTreeMap<String, toml::Value>,
+    /// The commit each remote-sourced package is currently checked out at, keyed by package
+    /// name, so re-deploys can skip re-fetching an unchanged ref.
+    #[serde(default)]
+    pub remote_commits: BTreeMap<String, String>,
+    /// The configured `rev` each remote-sourced package was last synced against, keyed by
+    /// package name, so a re-deploy can tell the ref changed and force a re-fetch even though a
+    /// checkout and a cached commit both still exist.
+    #[serde(default)]
+    pub remote_rev: BTreeMap<String, Option<String>>,
+}
+
+/// Loads the global and local configuration files, resolving any `include`s each declares,
+/// merges them (local overriding global) and applies an optional manual patch on top. The global
+/// config is resolved first and fed in as `local_config`'s starting point, so a `local_config`
+/// (or one of its includes) can `unset` something the global config (or one of *its* includes)
+/// defined, while a later layer re-defining the same key always wins over an earlier `unset`.
+pub fn load_configuration(
+    local_config: &Path,
+    global_config: &Path,
+    patch: Option<Package>,
+) -> Result<Configuration> {
+    let mut config = Configuration::default();
+
+    if global_config.exists() {
+        let mut visited = Vec::new();
+        config = load_layer(global_config, &mut visited, &config)?;
+    }
+    if local_config.exists() {
+        let mut visited = Vec::new();
+        config = load_layer(local_config, &mut visited, &config)?;
+    }
+
+    if let Some(patch) = patch {
+        config.packages.insert("patch".to_string(), patch);
+    }
+
+    Ok(config)
+}
+
+/// Reads `path` and recursively resolves its `include`s on top of `base` (everything merged by
+/// outer layers so far), applies this file's own `unset` directives against that (so it can
+/// subtract something `base` or one of its own includes defined), then merges this file's own
+/// `packages`/`variables`/`variable_definitions` on top -- so a layer's own definitions always
+/// win over its own (or any earlier layer's) `unset`.
+fn load_layer(path: &Path, visited: &mut Vec<PathBuf>, base: &Configuration) -> Result<Configuration> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("canonicalize config path {:?}", path))?;
+
+    if let Some(pos) = visited.iter().position(|p| p == &canonical) {
+        let mut chain: Vec<String> = visited[pos..]
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        chain.push(path.display().to_string());
+        return Err(anyhow!("include cycle detected: {}", chain.join(" -> ")));
+    }
+    visited.push(canonical);
+
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("read config file {:?}", path))?;
+    let raw: RawConfiguration =
+        toml::from_str(&contents).with_context(|| format!("parse config file {:?}", path))?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut merged = base.clone();
+    for include in &raw.include {
+        let include_path = dir.join(include);
+        merged = load_layer(&include_path, visited, &merged)
+            .with_context(|| format!("resolve include {:?} from {:?}", include, path))?;
+    }
+
+    apply_unsets(&mut merged, &raw.unset)
+        .with_context(|| format!("apply unset directives from {:?}", path))?;
+
+    merge_in(
+        &mut merged,
+        Configuration {
+            packages: raw.packages,
+            variables: raw.variables,
+            variable_definitions: raw.variable_definitions,
+        },
+    );
+
+    visited.pop();
+    Ok(merged)
+}
+
+fn merge_in(base: &mut Configuration, overlay: Configuration) {
+    base.packages.extend(overlay.packages);
+    base.variables.extend(overlay.variables);
+    base.variable_definitions.extend(overlay.variable_definitions);
+}
+
+/// Removes keys named by dotted paths like `packages.foo` or `variables.bar` from `config`, so
+/// a layer can subtract something an included/global layer contributed rather than merely
+/// shadowing it with an empty value.
+fn apply_unsets(config: &mut Configuration, unset: &[String]) -> Result<()> {
+    for path in unset {
+        let (section, key) = path
+            .split_once('.')
+            .ok_or_else(|| anyhow!("invalid unset path {:?}, expected `section.key`", path))?;
+        match section {
+            "packages" => {
+                config.packages.remove(key);
+            }
+            "variables" => {
+                config.variables.remove(key);
+            }
+            other => bail!(
+                "cannot unset {:?}: unknown section {:?} (expected `packages` or `variables`)",
+                path,
+                other
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A fresh, empty directory under the system temp dir, named after the calling test so
+    /// parallel test runs don't collide, cleaned up (best-effort) before each use.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dotter_config_test_{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn include_chain_merges_in_order() {
+        let dir = test_dir("include_chain_merges_in_order");
+
+        fs::write(
+            dir.join("base.toml"),
+            r#"
+            [variables]
+            v = "from_base"
+
+            [packages.base]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("mid.toml"),
+            r#"
+            include = ["base.toml"]
+
+            [variables]
+            w = "from_mid"
+
+            [packages.mid]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("top.toml"),
+            r#"
+            include = ["mid.toml"]
+
+            [variables]
+            v = "from_top"
+
+            [packages.top]
+            "#,
+        )
+        .unwrap();
+
+        let mut visited = Vec::new();
+        let config = load_layer(&dir.join("top.toml"), &mut visited, &Configuration::default())
+            .unwrap();
+
+        // `top` overrides `base`'s value for a variable they both set...
+        assert_eq!(
+            config.variables.get("v"),
+            Some(&toml::Value::String("from_top".into()))
+        );
+        // ...while a variable only `mid` sets passes through untouched.
+        assert_eq!(
+            config.variables.get("w"),
+            Some(&toml::Value::String("from_mid".into()))
+        );
+        // Every layer's packages are present.
+        assert!(config.packages.contains_key("base"));
+        assert!(config.packages.contains_key("mid"));
+        assert!(config.packages.contains_key("top"));
+    }
+
+    #[test]
+    fn include_cycle_is_detected() {
+        let dir = test_dir("include_cycle_is_detected");
+
+        fs::write(dir.join("a.toml"), r#"include = ["b.toml"]"#).unwrap();
+        fs::write(dir.join("b.toml"), r#"include = ["a.toml"]"#).unwrap();
+
+        let mut visited = Vec::new();
+        let err = load_layer(&dir.join("a.toml"), &mut visited, &Configuration::default())
+            .unwrap_err();
+
+        let message = format!("{err:#}");
+        assert!(
+            message.contains("include cycle detected"),
+            "unexpected error: {message}"
+        );
+        assert!(message.contains("a.toml"), "unexpected error: {message}");
+        assert!(message.contains("b.toml"), "unexpected error: {message}");
+    }
+
+    #[test]
+    fn unset_removes_inherited_package_and_variable() {
+        let dir = test_dir("unset_removes_inherited_package_and_variable");
+
+        fs::write(
+            dir.join("base.toml"),
+            r#"
+            [variables]
+            v = "from_base"
+
+            [packages.foo]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("local.toml"),
+            r#"
+            include = ["base.toml"]
+            unset = ["packages.foo", "variables.v"]
+            "#,
+        )
+        .unwrap();
+
+        let mut visited = Vec::new();
+        let config = load_layer(&dir.join("local.toml"), &mut visited, &Configuration::default())
+            .unwrap();
+
+        assert!(!config.packages.contains_key("foo"));
+        assert!(!config.variables.contains_key("v"));
+    }
+
+    #[test]
+    fn redefining_a_key_wins_over_an_earlier_unset() {
+        let dir = test_dir("redefining_a_key_wins_over_an_earlier_unset");
+
+        fs::write(
+            dir.join("base.toml"),
+            r#"
+            [packages.foo]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("mid.toml"),
+            r#"
+            include = ["base.toml"]
+            unset = ["packages.foo"]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("top.toml"),
+            r#"
+            include = ["mid.toml"]
+
+            [packages.foo]
+            "#,
+        )
+        .unwrap();
+
+        let mut visited = Vec::new();
+        let config = load_layer(&dir.join("top.toml"), &mut visited, &Configuration::default())
+            .unwrap();
+
+        // `mid` unsets `foo`, but `top` (which includes `mid`) redefines it afterwards, so the
+        // redefinition should win.
+        assert!(config.packages.contains_key("foo"));
+    }
+
+    #[test]
+    fn local_config_can_unset_a_package_the_global_config_defined() {
+        let dir = test_dir("local_config_can_unset_a_package_the_global_config_defined");
+
+        let global_path = dir.join("global.toml");
+        let local_path = dir.join("local.toml");
+        fs::write(
+            &global_path,
+            r#"
+            [packages.foo]
+            "#,
+        )
+        .unwrap();
+        fs::write(&local_path, r#"unset = ["packages.foo"]"#).unwrap();
+
+        let config = load_configuration(&local_path, &global_path, None).unwrap();
+
+        assert!(!config.packages.contains_key("foo"));
+    }
+
+    fn definition(ty: VariableType) -> VariableDefinition {
+        VariableDefinition {
+            ty,
+            default: None,
+            prompt: None,
+        }
+    }
+
+    #[test]
+    fn parse_string_accepts_anything() {
+        let def = definition(VariableType::String);
+        assert_eq!(
+            def.parse("anything").unwrap(),
+            toml::Value::String("anything".into())
+        );
+    }
+
+    #[test]
+    fn parse_bool_accepts_common_spellings() {
+        let def = definition(VariableType::Bool);
+        for truthy in ["y", "yes", "true", "TRUE", "Yes"] {
+            assert_eq!(def.parse(truthy).unwrap(), toml::Value::Boolean(true));
+        }
+        for falsy in ["n", "no", "false", "FALSE"] {
+            assert_eq!(def.parse(falsy).unwrap(), toml::Value::Boolean(false));
+        }
+    }
+
+    #[test]
+    fn parse_bool_rejects_anything_else() {
+        let def = definition(VariableType::Bool);
+        assert!(def.parse("maybe").is_err());
+        assert!(def.parse("").is_err());
+    }
+
+    #[test]
+    fn parse_enum_accepts_only_declared_choices() {
+        let def = definition(VariableType::Enum {
+            choices: vec!["a".into(), "b".into()],
+        });
+        assert_eq!(def.parse("a").unwrap(), toml::Value::String("a".into()));
+        assert!(def.parse("c").is_err());
+    }
+
+    #[test]
+    fn validate_catches_a_default_of_the_wrong_type() {
+        let bool_def = definition(VariableType::Bool);
+        assert!(bool_def.validate(&toml::Value::String("true".into())).is_err());
+        assert!(bool_def.validate(&toml::Value::Boolean(true)).is_ok());
+
+        let enum_def = definition(VariableType::Enum {
+            choices: vec!["a".into(), "b".into()],
+        });
+        assert!(enum_def.validate(&toml::Value::String("c".into())).is_err());
+        assert!(enum_def.validate(&toml::Value::String("a".into())).is_ok());
+    }
+}
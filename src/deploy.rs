@@ -2,10 +2,14 @@ use anyhow::{Context, Result};
 
 use config::Cache;
 use filesystem::load_file;
+use futures::{future::FutureExt, StreamExt};
 use handlebars_helpers::create_new_handlebars;
 
-use std::io::{self, Read};
-use std::path::Path;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
 use crate::args::Options;
 use crate::config;
@@ -14,8 +18,13 @@ use crate::file_state::{file_state_from_configuration, FileState};
 use crate::filesystem;
 use crate::handlebars_helpers;
 use crate::hooks;
+use crate::remote;
 use crate::{actions::Action, filesystem::Filesystem};
 
+/// How long to wait after the first detected change before redeploying, so a burst of saves
+/// (e.g. an editor writing a swap file then the real file) only triggers one redeploy.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
 /// Returns true if an error was printed
 pub fn deploy(opt: &Options) -> Result<bool> {
     let mut patch = None;
@@ -29,6 +38,18 @@ pub fn deploy(opt: &Options) -> Result<bool> {
     }
     trace!("Manual patch: {:#?}", patch);
 
+    let error_occurred = run_deploy_once(opt, patch.clone())?;
+
+    if opt.watch {
+        watch_and_redeploy(opt, patch)?;
+    }
+
+    Ok(error_occurred)
+}
+
+/// Runs a single plan-and-apply deploy cycle: load configuration and cache fresh from disk,
+/// compute the file state, and execute the resulting actions.
+fn run_deploy_once(opt: &Options, patch: Option<config::Package>) -> Result<bool> {
     let mut config = config::load_configuration(&opt.local_config, &opt.global_config, patch)
         .context("get a configuration")?;
 
@@ -39,7 +60,13 @@ pub fn deploy(opt: &Options) -> Result<bool> {
         config::Cache::default()
     };
 
-    let state = file_state_from_configuration(&config, &cache, &opt.cache_directory)
+    prompt_for_variables(&mut config, &mut cache, opt).context("prompt for variable values")?;
+
+    let remote_roots =
+        remote::sync_remote_sources(&config, &mut cache, &opt.cache_directory, opt.update)
+            .context("sync remote package sources")?;
+
+    let state = file_state_from_configuration(&config, &cache, &remote_roots, &opt.cache_directory)
         .context("get file state")?;
     trace!("File state: {:#?}", state);
 
@@ -56,32 +83,22 @@ pub fn deploy(opt: &Options) -> Result<bool> {
         .context("run pre-deploy hook")?;
     }
 
-    let mut suggest_force = false;
-    let mut error_occurred = false;
-
     let plan = plan_deploy(state);
-    let (mut real_fs, mut dry_run_fs);
-    let fs: &mut dyn Filesystem = if opt.act {
-        real_fs = crate::filesystem::RealFilesystem::new(opt.interactive);
-        &mut real_fs
+
+    let (suggest_force, mut error_occurred) = if opt.act && !opt.interactive {
+        run_actions_parallel(plan, opt, &handlebars, &config.variables, &mut cache)
+    } else if opt.act {
+        // Interactive confirmation prompts can't interleave sanely across worker threads, so
+        // fall back to the sequential path whenever one might be needed.
+        let mut fs = crate::filesystem::RealFilesystem::new(opt.interactive);
+        run_actions_sequential(plan, &mut fs, opt, &handlebars, &config.variables, &mut cache)
     } else {
-        dry_run_fs = crate::filesystem::DryRunFilesystem::new();
-        &mut dry_run_fs
+        // Dry-run output must stay deterministic, and `plan` is already in a deterministic
+        // order, so there's nothing to gain from parallelizing it.
+        let mut fs = crate::filesystem::DryRunFilesystem::new();
+        run_actions_sequential(plan, &mut fs, opt, &handlebars, &config.variables, &mut cache)
     };
 
-    for action in plan {
-        match action.run(fs, opt, &handlebars, &config.variables) {
-            Ok(true) => action.affect_cache(&mut cache),
-            Ok(false) => {
-                suggest_force = true;
-            }
-            Err(e) => {
-                error_occurred = true;
-                display_error(e);
-            }
-        }
-    }
-
     trace!("Actual symlinks: {:#?}", cache.symlinks);
     trace!("Actual templates: {:#?}", cache.templates);
 
@@ -108,6 +125,268 @@ pub fn deploy(opt: &Options) -> Result<bool> {
     Ok(error_occurred)
 }
 
+/// Prompts for any `variable_definitions` entry that isn't already set in `config.variables`,
+/// validates the answer against its declared type, and feeds it into the variable map used for
+/// rendering. Reuses a previously-recorded answer from `cache` unless `--reconfigure` is passed,
+/// and only prompts at all when running for real on an interactive terminal.
+fn prompt_for_variables(config: &mut config::Configuration, cache: &mut Cache, opt: &Options) -> Result<()> {
+    if config.variable_definitions.is_empty() {
+        return Ok(());
+    }
+    let can_prompt = opt.act && io::stdin().is_terminal();
+
+    for (name, definition) in &config.variable_definitions {
+        if config.variables.contains_key(name) {
+            continue;
+        }
+
+        if !opt.reconfigure {
+            if let Some(answer) = cache.variable_answers.get(name) {
+                config.variables.insert(name.clone(), answer.clone());
+                continue;
+            }
+        }
+        if !can_prompt {
+            continue;
+        }
+
+        let value = prompt_variable(name, definition)?;
+        cache.variable_answers.insert(name.clone(), value.clone());
+        config.variables.insert(name.clone(), value);
+    }
+
+    Ok(())
+}
+
+/// Repeatedly prompts on stdin for `name` until a value parses against `definition`'s type, or
+/// an empty line is entered while a default is available.
+fn prompt_variable(name: &str, definition: &config::VariableDefinition) -> Result<toml::Value> {
+    loop {
+        match &definition.prompt {
+            Some(prompt) => print!("{prompt}"),
+            None => print!("{name}"),
+        }
+        if let Some(default) = &definition.default {
+            print!(" [{default}]");
+        }
+        print!(": ");
+        io::stdout().flush().context("flush prompt to stdout")?;
+
+        let mut input = String::new();
+        io::stdin()
+            .read_line(&mut input)
+            .context("read variable value from stdin")?;
+        let input = input.trim();
+
+        if input.is_empty() {
+            if let Some(default) = &definition.default {
+                match definition.validate(default) {
+                    Ok(()) => return Ok(default.clone()),
+                    Err(e) => {
+                        error!("Invalid default value for {:?}: {}", name, e);
+                        continue;
+                    }
+                }
+            }
+        }
+
+        match definition.parse(input) {
+            Ok(value) => return Ok(value),
+            Err(e) => error!("Invalid value for {:?}: {}", name, e),
+        }
+    }
+}
+
+/// After an initial deploy, keep re-running deploy cycles as template/symlink sources and
+/// config files change, until interrupted. Used by `--watch`.
+fn watch_and_redeploy(opt: &Options, patch: Option<config::Package>) -> Result<()> {
+    let (real_fs, dry_run_fs);
+    let fs: &dyn Filesystem = if opt.act {
+        real_fs = crate::filesystem::RealFilesystem::new(opt.interactive);
+        &real_fs
+    } else {
+        // DryRunFilesystem::watch() is a no-op stream, so a dry-run watch never sees a change
+        // and simply blocks forever -- there's nothing meaningful to preview-redeploy on.
+        dry_run_fs = crate::filesystem::DryRunFilesystem::new();
+        &dry_run_fs
+    };
+
+    loop {
+        let paths = watched_paths(opt)?;
+        if paths.is_empty() {
+            warn!("Nothing to watch for changes.");
+            return Ok(());
+        }
+        info!("Watching {} path(s) for changes...", paths.len());
+
+        // `watch` is already boxed and pinned (so it stays callable through `&dyn Filesystem`),
+        // so it can be polled directly without a `pin_mut!`.
+        let mut stream = fs.watch(paths);
+
+        // Block until the first change, then drain whatever else arrives within the debounce
+        // window so a burst of events collapses into a single redeploy.
+        match futures::executor::block_on(stream.next()) {
+            Some(changed) => debug!("Detected change at {:?}", changed),
+            None => return Ok(()), // watch stream ended
+        }
+        std::thread::sleep(WATCH_DEBOUNCE);
+        while stream.as_mut().next().now_or_never().flatten().is_some() {}
+
+        info!("Re-running deploy...");
+        match run_deploy_once(opt, patch.clone()) {
+            Ok(true) => error!("Some files were skipped while redeploying."),
+            Ok(false) => {}
+            Err(e) => display_error(e),
+        }
+    }
+}
+
+/// Collects every path that a change to should trigger a redeploy: the config files themselves
+/// plus every desired symlink/template source.
+fn watched_paths(opt: &Options) -> Result<Vec<PathBuf>> {
+    let config = config::load_configuration(&opt.local_config, &opt.global_config, None)
+        .context("get a configuration for watch paths")?;
+    let mut cache = load_file(&opt.cache_file)?.unwrap_or_default();
+    let remote_roots =
+        remote::sync_remote_sources(&config, &mut cache, &opt.cache_directory, false)
+            .context("sync remote package sources for watch paths")?;
+    let state = file_state_from_configuration(&config, &cache, &remote_roots, &opt.cache_directory)
+        .context("get file state for watch paths")?;
+
+    let mut paths = vec![opt.local_config.clone(), opt.global_config.clone()];
+    paths.extend(state.desired_symlinks.iter().map(|s| s.source.clone()));
+    paths.extend(state.desired_templates.iter().map(|t| t.source.clone()));
+    paths.sort();
+    paths.dedup();
+
+    Ok(paths)
+}
+
+/// Runs `plan` one action at a time, in order. Used for dry runs, where output must stay
+/// deterministic, and for interactive deploys, whose confirmation prompts can't interleave
+/// across worker threads.
+fn run_actions_sequential(
+    plan: Vec<Action>,
+    fs: &mut dyn Filesystem,
+    opt: &Options,
+    handlebars: &handlebars::Handlebars,
+    variables: &config::Variables,
+    cache: &mut Cache,
+) -> (bool, bool) {
+    let mut suggest_force = false;
+    let mut error_occurred = false;
+
+    for action in plan {
+        match action.run(fs, opt, handlebars, variables) {
+            Ok(true) => action.affect_cache(cache),
+            Ok(false) => suggest_force = true,
+            Err(e) => {
+                error_occurred = true;
+                display_error(e);
+            }
+        }
+    }
+
+    (suggest_force, error_occurred)
+}
+
+/// Runs `plan` across a pool of worker threads. Actions whose target lives in the same parent
+/// directory as another action's are serialized against each other (grouped together), since
+/// they may race to create that directory or otherwise conflict; actions in different groups
+/// have no such overlap and are dispatched concurrently. Groups are pulled from a single shared
+/// queue -- rather than split into fixed per-worker batches -- so an idle worker picks up the
+/// next group as soon as it's free instead of waiting on whichever group in its batch is
+/// slowest. Each worker gets its own [`RealFilesystem`](crate::filesystem::RealFilesystem)
+/// handle, since there's no shared state to protect there; `cache` is still shared behind a
+/// mutex so `affect_cache` stays correct regardless of which worker finishes first, and `stderr`
+/// is serialized separately so errors from different workers don't interleave.
+fn run_actions_parallel(
+    plan: Vec<Action>,
+    opt: &Options,
+    handlebars: &handlebars::Handlebars,
+    variables: &config::Variables,
+    cache: &mut Cache,
+) -> (bool, bool) {
+    let queue: VecDeque<Vec<Action>> = group_by_target_directory(plan).into_iter().collect();
+
+    let worker_count = std::thread::available_parallelism()
+        .map(std::num::NonZeroUsize::get)
+        .unwrap_or(1)
+        .max(1)
+        .min(queue.len().max(1));
+
+    let queue = Mutex::new(queue);
+    let cache = Mutex::new(cache);
+    let suggest_force = Mutex::new(false);
+    let error_occurred = Mutex::new(false);
+    let stderr_lock = Mutex::new(());
+
+    std::thread::scope(|scope| {
+        for _ in 0..worker_count {
+            scope.spawn(|| {
+                // `opt.interactive` is never true here: `run_deploy_once` routes interactive
+                // deploys through the sequential path instead, since a confirmation prompt can't
+                // interleave sanely across worker threads.
+                let mut fs = crate::filesystem::RealFilesystem::new(false);
+                loop {
+                    let Some(group) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    for action in &group {
+                        match action.run(&mut fs, opt, handlebars, variables) {
+                            Ok(true) => action.affect_cache(&mut **cache.lock().unwrap()),
+                            Ok(false) => *suggest_force.lock().unwrap() = true,
+                            Err(e) => {
+                                *error_occurred.lock().unwrap() = true;
+                                let _guard = stderr_lock.lock().unwrap();
+                                display_error(e);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    (
+        suggest_force.into_inner().unwrap(),
+        error_occurred.into_inner().unwrap(),
+    )
+}
+
+/// Groups actions so that every action sharing a target's parent directory with another ends up
+/// in the same group (and therefore runs serialized against it), while actions in unrelated
+/// directories land in independent groups that [`run_actions_parallel`] can dispatch at once.
+fn group_by_target_directory(plan: Vec<Action>) -> Vec<Vec<Action>> {
+    let mut groups: Vec<Vec<Action>> = Vec::new();
+    let mut index_by_directory: HashMap<PathBuf, usize> = HashMap::new();
+
+    for action in plan {
+        let directory = target_directory(&action);
+        let index = *index_by_directory.entry(directory).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[index].push(action);
+    }
+
+    groups
+}
+
+/// The canonicalized parent directory of an action's target, or its literal (un-canonicalized)
+/// parent if the directory doesn't exist yet -- e.g. on a first deploy, before any
+/// `create_dir_all` has run.
+fn target_directory(action: &Action) -> PathBuf {
+    let target = match action {
+        Action::CreateSymlink(d) | Action::UpdateSymlink(d) => &d.target.target,
+        Action::DeleteSymlink { target, .. } => target,
+        Action::CreateTemplate(d) | Action::UpdateTemplate(d) => &d.target.target,
+        Action::DeleteTemplate { target, .. } => target,
+    };
+    let parent = target.parent().unwrap_or_else(|| Path::new(""));
+    parent.canonicalize().unwrap_or_else(|_| parent.to_path_buf())
+}
+
 pub fn undeploy(opt: Options) -> Result<bool> {
     let mut config = config::load_configuration(&opt.local_config, &opt.global_config, None)
         .context("get a configuration")?;
@@ -282,6 +561,7 @@ mod test {
                 owner: None,
                 append: None,
                 prepend: None,
+                line_ending: None,
             },
             cache: "cache/b_cache".into(),
         };
@@ -395,4 +675,26 @@ mod test {
             .run(&mut fs, &options, &handlebars, &variables)
             .unwrap();
     }
+
+    #[test]
+    fn group_by_target_directory_groups_shared_parents_and_splits_distinct_ones() {
+        let a = Action::DeleteSymlink {
+            source: "a_in".into(),
+            target: "dir1/a_out".into(),
+        };
+        let b = Action::DeleteSymlink {
+            source: "b_in".into(),
+            target: "dir1/b_out".into(),
+        };
+        let c = Action::DeleteSymlink {
+            source: "c_in".into(),
+            target: "dir2/c_out".into(),
+        };
+
+        let groups = group_by_target_directory(vec![a.clone(), b.clone(), c.clone()]);
+
+        assert_eq!(groups.len(), 2);
+        assert!(groups.contains(&vec![a, b]));
+        assert!(groups.contains(&vec![c]));
+    }
 }
@@ -0,0 +1,118 @@
+//! Resolves a loaded [`Configuration`] and [`Cache`] into the concrete set of symlinks and
+//! templates that should exist (desired) versus what the last deploy actually put in place
+//! (existing), for [`crate::deploy::plan_deploy`] to diff.
+
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Cache, Configuration, SymbolicTarget, TemplateTarget};
+
+/// A desired or existing symlink: `source` (resolved against its package's root) should be symlinked at
+/// `target.target`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SymlinkDescription {
+    pub source: PathBuf,
+    pub target: SymbolicTarget,
+}
+
+/// A desired or existing template: `source` is rendered into `cache`, which is then copied to
+/// `target.target`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TemplateDescription {
+    pub source: PathBuf,
+    pub target: TemplateTarget,
+    pub cache: PathBuf,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileState {
+    pub desired_symlinks: BTreeSet<SymlinkDescription>,
+    pub desired_templates: BTreeSet<TemplateDescription>,
+    pub existing_symlinks: BTreeSet<SymlinkDescription>,
+    pub existing_templates: BTreeSet<TemplateDescription>,
+}
+
+/// Builds the desired file state from `config` (resolving each package's symlink/template
+/// sources against its root -- a remote checkout in `remote_roots` if it has one, or the
+/// dotfiles repo itself otherwise) and the existing file state from `cache` (what the previous
+/// deploy recorded, before this one potentially changes it).
+pub fn file_state_from_configuration(
+    config: &Configuration,
+    cache: &Cache,
+    remote_roots: &BTreeMap<String, PathBuf>,
+    cache_directory: &Path,
+) -> Result<FileState> {
+    let mut desired_symlinks = BTreeSet::new();
+    let mut desired_templates = BTreeSet::new();
+
+    for (name, package) in &config.packages {
+        let root = remote_roots.get(name).cloned().unwrap_or_default();
+
+        for (source, target) in &package.symlinks {
+            desired_symlinks.insert(SymlinkDescription {
+                source: root.join(source),
+                target: target.clone(),
+            });
+        }
+
+        for (source, target) in &package.templates {
+            desired_templates.insert(TemplateDescription {
+                source: root.join(source),
+                cache: cache_directory.join(source),
+                target: target.clone(),
+            });
+        }
+    }
+
+    // The cache only ever recorded a source -> target mapping (owner/append/prepend/line_ending
+    // aren't re-derivable from a deployed file), so an existing entry whose source and target
+    // path match a desired one takes that desired entry's details, and `plan_deploy` diffs the
+    // two sides by source and target path.
+    let existing_symlinks = cache
+        .symlinks
+        .iter()
+        .map(|(source, target)| {
+            desired_symlinks
+                .iter()
+                .find(|d: &&SymlinkDescription| &d.source == source && &d.target.target == target)
+                .cloned()
+                .unwrap_or_else(|| SymlinkDescription {
+                    source: source.clone(),
+                    target: SymbolicTarget {
+                        target: target.clone(),
+                        owner: None,
+                    },
+                })
+        })
+        .collect();
+
+    let existing_templates = cache
+        .templates
+        .iter()
+        .map(|(source, target)| {
+            desired_templates
+                .iter()
+                .find(|d: &&TemplateDescription| &d.source == source && &d.target.target == target)
+                .cloned()
+                .unwrap_or_else(|| TemplateDescription {
+                    source: source.clone(),
+                    cache: cache_directory.join(source),
+                    target: TemplateTarget {
+                        target: target.clone(),
+                        owner: None,
+                        append: None,
+                        prepend: None,
+                        line_ending: None,
+                    },
+                })
+        })
+        .collect();
+
+    Ok(FileState {
+        desired_symlinks,
+        desired_templates,
+        existing_symlinks,
+        existing_templates,
+    })
+}
@@ -0,0 +1,460 @@
+//! Abstraction over the filesystem operations a deploy/undeploy needs, so dry runs can log what
+//! would happen instead of doing it, and so tests can assert on an exact sequence of operations
+//! without touching disk.
+
+use anyhow::{Context, Result};
+use futures::channel::mpsc::unbounded;
+use futures::Stream;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use crate::config::LineEnding;
+
+/// The result of comparing a desired symlink against whatever currently exists at its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkComparison {
+    /// Nothing exists at the target yet.
+    OnlySourceExists,
+    /// A symlink already exists at the target and points at the desired source.
+    Identical,
+    /// Something exists at the target, but it isn't the desired symlink.
+    Changed,
+}
+
+/// The result of comparing a desired template against whatever currently exists at its target
+/// and in the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateComparison {
+    /// Neither the cache nor the target exist yet.
+    BothMissing,
+    /// The cached copy and the deployed target are both still what was last rendered.
+    Identical,
+    /// The target (or the cache) no longer matches what was last rendered.
+    Changed,
+}
+
+/// Filesystem operations needed to deploy/undeploy symlinks and templates, and to watch their
+/// sources for `--watch`. Implemented for real by [`RealFilesystem`], as a no-op logger by
+/// [`DryRunFilesystem`], and mocked in tests.
+#[cfg_attr(test, mockall::automock)]
+pub trait Filesystem {
+    fn compare_symlink(&mut self, source: &Path, target: &Path) -> Result<SymlinkComparison>;
+    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison>;
+
+    fn create_dir_all(&mut self, path: &Path, owner: Option<String>) -> Result<()>;
+    fn make_symlink(&mut self, link: &Path, target: &Path, owner: Option<String>) -> Result<()>;
+
+    fn read_to_string(&mut self, path: &Path) -> Result<String>;
+    fn write(&mut self, path: &Path, content: String) -> Result<()>;
+    fn copy_file(&mut self, from: &Path, to: &Path, owner: Option<String>) -> Result<()>;
+    fn copy_permissions(&mut self, from: &Path, to: &Path, owner: Option<String>) -> Result<()>;
+
+    fn remove(&mut self, path: &Path) -> Result<()>;
+
+    /// Watches `paths` for changes, yielding each changed path as it's reported. The returned
+    /// stream is boxed and pinned (rather than an RPITIT `impl Stream`) so it stays callable
+    /// through `&dyn Filesystem`.
+    fn watch(&self, paths: Vec<PathBuf>) -> Pin<Box<dyn Stream<Item = PathBuf> + Send>>;
+}
+
+/// Performs operations for real, against the actual filesystem.
+pub struct RealFilesystem {
+    /// Whether `write` asks for confirmation before overwriting a file that already exists; it
+    /// doesn't check whether that file came from a previous deploy.
+    interactive: bool,
+}
+
+impl RealFilesystem {
+    pub fn new(interactive: bool) -> RealFilesystem {
+        RealFilesystem { interactive }
+    }
+}
+
+impl Filesystem for RealFilesystem {
+    fn compare_symlink(&mut self, source: &Path, target: &Path) -> Result<SymlinkComparison> {
+        match fs::symlink_metadata(target) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                match fs::read_link(target) {
+                    Ok(existing) if existing == source => Ok(SymlinkComparison::Identical),
+                    _ => Ok(SymlinkComparison::Changed),
+                }
+            }
+            Ok(_) => Ok(SymlinkComparison::Changed),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(SymlinkComparison::OnlySourceExists)
+            }
+            Err(e) => Err(e).with_context(|| format!("check existing symlink at {:?}", target)),
+        }
+    }
+
+    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
+        let target_content = match fs::read_to_string(target) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("read template target {:?}", target)),
+        };
+        let cache_content = match fs::read_to_string(cache) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("read cached template {:?}", cache)),
+        };
+
+        match (target_content, cache_content) {
+            (None, None) => Ok(TemplateComparison::BothMissing),
+            (Some(target_content), Some(cache_content)) => {
+                // Normalize both sides' line endings before comparing, so a target that's been
+                // re-saved by an editor that flips line endings isn't flagged as changed.
+                if LineEnding::Lf.normalize(&target_content)
+                    == LineEnding::Lf.normalize(&cache_content)
+                {
+                    Ok(TemplateComparison::Identical)
+                } else {
+                    Ok(TemplateComparison::Changed)
+                }
+            }
+            _ => Ok(TemplateComparison::Changed),
+        }
+    }
+
+    fn create_dir_all(&mut self, path: &Path, owner: Option<String>) -> Result<()> {
+        if path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        fs::create_dir_all(path).with_context(|| format!("create directory {:?}", path))?;
+        if let Some(owner) = owner {
+            set_owner(path, &owner)?;
+        }
+        Ok(())
+    }
+
+    fn make_symlink(&mut self, link: &Path, target: &Path, owner: Option<String>) -> Result<()> {
+        if fs::symlink_metadata(link).is_ok() {
+            fs::remove_file(link).with_context(|| format!("remove existing file at {:?}", link))?;
+        }
+        symlink(target, link).with_context(|| format!("create symlink {:?} -> {:?}", link, target))?;
+        if let Some(owner) = owner {
+            set_owner(link, &owner)?;
+        }
+        Ok(())
+    }
+
+    fn read_to_string(&mut self, path: &Path) -> Result<String> {
+        fs::read_to_string(path).with_context(|| format!("read {:?}", path))
+    }
+
+    fn write(&mut self, path: &Path, content: String) -> Result<()> {
+        if self.interactive && path.exists() {
+            if !confirm_overwrite(path)? {
+                anyhow::bail!("not overwriting {:?} without confirmation", path);
+            }
+        }
+        fs::write(path, content).with_context(|| format!("write {:?}", path))
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path, owner: Option<String>) -> Result<()> {
+        fs::copy(from, to).with_context(|| format!("copy {:?} to {:?}", from, to))?;
+        if let Some(owner) = owner {
+            set_owner(to, &owner)?;
+        }
+        Ok(())
+    }
+
+    fn copy_permissions(&mut self, from: &Path, to: &Path, owner: Option<String>) -> Result<()> {
+        let permissions = fs::metadata(from)
+            .with_context(|| format!("read metadata of {:?}", from))?
+            .permissions();
+        fs::set_permissions(to, permissions)
+            .with_context(|| format!("set permissions of {:?}", to))?;
+        if let Some(owner) = owner {
+            set_owner(to, &owner)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => {
+                fs::remove_dir_all(path).with_context(|| format!("remove directory {:?}", path))
+            }
+            Ok(_) => fs::remove_file(path).with_context(|| format!("remove file {:?}", path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("check {:?} before removing", path)),
+        }
+    }
+
+    fn watch(&self, paths: Vec<PathBuf>) -> Pin<Box<dyn Stream<Item = PathBuf> + Send>> {
+        let (tx, rx) = unbounded();
+
+        // The watcher is moved into the closure so it stays alive for as long as the stream is
+        // polled; once the receiver (and the closure holding `watcher`) is dropped, watching
+        // stops.
+        let watcher_result: notify::Result<RecommendedWatcher> =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    for path in event.paths {
+                        let _ = tx.unbounded_send(path);
+                    }
+                }
+            });
+
+        let mut watcher = match watcher_result {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                error!("Failed to start filesystem watcher: {:#}", e);
+                return Box::pin(futures::stream::pending());
+            }
+        };
+        for path in &paths {
+            if let Err(e) = watcher.watch(path, RecursiveMode::NonRecursive) {
+                warn!("Failed to watch {:?} for changes: {:#}", path, e);
+            }
+        }
+
+        // Keep the watcher alive alongside the stream it feeds.
+        Box::pin(WatchStream { _watcher: watcher, rx })
+    }
+}
+
+struct WatchStream {
+    _watcher: RecommendedWatcher,
+    rx: futures::channel::mpsc::UnboundedReceiver<PathBuf>,
+}
+
+impl Stream for WatchStream {
+    type Item = PathBuf;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.rx).poll_next(cx)
+    }
+}
+
+/// Logs what would happen instead of touching the filesystem, for `--dry-run`.
+pub struct DryRunFilesystem {}
+
+impl DryRunFilesystem {
+    pub fn new() -> DryRunFilesystem {
+        DryRunFilesystem {}
+    }
+}
+
+impl Default for DryRunFilesystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filesystem for DryRunFilesystem {
+    fn compare_symlink(&mut self, _source: &Path, target: &Path) -> Result<SymlinkComparison> {
+        Ok(if target.exists() || fs::symlink_metadata(target).is_ok() {
+            SymlinkComparison::Changed
+        } else {
+            SymlinkComparison::OnlySourceExists
+        })
+    }
+
+    fn compare_template(&mut self, target: &Path, cache: &Path) -> Result<TemplateComparison> {
+        Ok(match (target.exists(), cache.exists()) {
+            (false, false) => TemplateComparison::BothMissing,
+            _ => TemplateComparison::Changed,
+        })
+    }
+
+    fn create_dir_all(&mut self, path: &Path, _owner: Option<String>) -> Result<()> {
+        info!("Would create directory {:?}", path);
+        Ok(())
+    }
+
+    fn make_symlink(&mut self, link: &Path, target: &Path, _owner: Option<String>) -> Result<()> {
+        info!("Would symlink {:?} -> {:?}", link, target);
+        Ok(())
+    }
+
+    fn read_to_string(&mut self, path: &Path) -> Result<String> {
+        fs::read_to_string(path).with_context(|| format!("read {:?}", path))
+    }
+
+    fn write(&mut self, path: &Path, _content: String) -> Result<()> {
+        info!("Would write {:?}", path);
+        Ok(())
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path, _owner: Option<String>) -> Result<()> {
+        info!("Would copy {:?} to {:?}", from, to);
+        Ok(())
+    }
+
+    fn copy_permissions(&mut self, from: &Path, to: &Path, _owner: Option<String>) -> Result<()> {
+        info!("Would copy permissions from {:?} to {:?}", from, to);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        info!("Would remove {:?}", path);
+        Ok(())
+    }
+
+    /// There is nothing meaningful to watch in a dry run, so this never yields and `--watch`
+    /// simply blocks forever rather than pretending to redeploy.
+    fn watch(&self, _paths: Vec<PathBuf>) -> Pin<Box<dyn Stream<Item = PathBuf> + Send>> {
+        Box::pin(futures::stream::pending())
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+#[cfg(unix)]
+fn set_owner(path: &Path, owner: &str) -> Result<()> {
+    let status = std::process::Command::new("chown")
+        .arg(owner)
+        .arg(path)
+        .status()
+        .with_context(|| format!("run chown {} {:?}", owner, path))?;
+    if !status.success() {
+        anyhow::bail!("chown {} {:?} failed", owner, path);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_owner(_path: &Path, _owner: &str) -> Result<()> {
+    anyhow::bail!("setting a file's owner is only supported on unix")
+}
+
+fn confirm_overwrite(path: &Path) -> Result<bool> {
+    use std::io::Write;
+    print!(
+        "{:?} already exists. Overwrite? [y/N]: ",
+        path
+    );
+    std::io::stdout().flush().context("flush prompt to stdout")?;
+
+    let mut input = String::new();
+    std::io::stdin()
+        .read_line(&mut input)
+        .context("read confirmation from stdin")?;
+    Ok(matches!(input.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Reads and parses a TOML file, returning `None` if it doesn't exist.
+pub fn load_file<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let value = toml::from_str(&content).with_context(|| format!("parse {:?}", path))?;
+            Ok(Some(value))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("read {:?}", path)),
+    }
+}
+
+/// Serializes a value as TOML and writes it to `path`, creating parent directories as needed.
+pub fn save_file<T: Serialize>(path: &Path, value: T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("create directory {:?}", parent))?;
+    }
+    let content = toml::to_string_pretty(&value).context("serialize to TOML")?;
+    fs::write(path, content).with_context(|| format!("write {:?}", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dotter_filesystem_test_{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn detect_finds_crlf_before_bare_lf() {
+        assert_eq!(LineEnding::detect("a\r\nb"), Some(LineEnding::Crlf));
+    }
+
+    #[test]
+    fn detect_finds_lf() {
+        assert_eq!(LineEnding::detect("a\nb"), Some(LineEnding::Lf));
+    }
+
+    #[test]
+    fn detect_finds_nothing_without_line_breaks() {
+        assert_eq!(LineEnding::detect("just one line"), None);
+    }
+
+    #[test]
+    fn normalize_lf_to_crlf_round_trips() {
+        let original = "a\nb\nc";
+        let crlf = LineEnding::Crlf.normalize(original);
+        assert_eq!(crlf, "a\r\nb\r\nc");
+        assert_eq!(LineEnding::Lf.normalize(&crlf), original);
+    }
+
+    #[test]
+    fn normalize_is_idempotent_on_already_matching_content() {
+        assert_eq!(LineEnding::Lf.normalize("a\nb"), "a\nb");
+        assert_eq!(LineEnding::Crlf.normalize("a\r\nb"), "a\r\nb");
+    }
+
+    #[test]
+    fn compare_template_treats_crlf_vs_lf_only_difference_as_identical() {
+        let dir = test_dir("compare_template_treats_crlf_vs_lf_only_difference_as_identical");
+        let target = dir.join("target");
+        let cache = dir.join("cache");
+        fs::write(&target, "a\r\nb\r\n").unwrap();
+        fs::write(&cache, "a\nb\n").unwrap();
+
+        let mut fs_impl = RealFilesystem::new(false);
+        assert_eq!(
+            fs_impl.compare_template(&target, &cache).unwrap(),
+            TemplateComparison::Identical
+        );
+    }
+
+    #[test]
+    fn compare_template_detects_a_real_change() {
+        let dir = test_dir("compare_template_detects_a_real_change");
+        let target = dir.join("target");
+        let cache = dir.join("cache");
+        fs::write(&target, "old content\n").unwrap();
+        fs::write(&cache, "new content\n").unwrap();
+
+        let mut fs_impl = RealFilesystem::new(false);
+        assert_eq!(
+            fs_impl.compare_template(&target, &cache).unwrap(),
+            TemplateComparison::Changed
+        );
+    }
+
+    #[test]
+    fn compare_template_reports_both_missing() {
+        let dir = test_dir("compare_template_reports_both_missing");
+        let target = dir.join("target");
+        let cache = dir.join("cache");
+
+        let mut fs_impl = RealFilesystem::new(false);
+        assert_eq!(
+            fs_impl.compare_template(&target, &cache).unwrap(),
+            TemplateComparison::BothMissing
+        );
+    }
+}
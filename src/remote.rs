@@ -0,0 +1,139 @@
+//! Fetching and caching package sources that live in a remote git repository, so a package's
+//! `symlinks`/`templates` can be resolved against a checked-out clone instead of only a path
+//! inside the dotfiles repo.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::{Cache, Configuration, RemoteSource};
+
+/// Directory, relative to the cache directory, that checked-out remote sources live under.
+const REMOTE_SOURCES_DIR: &str = "remotes";
+
+/// Ensures every package with a `source = { git = ... }` declaration has a local, shallow
+/// checkout pinned to its resolved commit, fetching only when the ref has moved or `update` is
+/// requested. Returns each such package's checkout root (with `subdirectory` applied), for
+/// `file_state_from_configuration` to resolve symlink/template sources against.
+pub fn sync_remote_sources(
+    config: &Configuration,
+    cache: &mut Cache,
+    cache_directory: &Path,
+    update: bool,
+) -> Result<BTreeMap<String, PathBuf>> {
+    let mut roots = BTreeMap::new();
+
+    for (name, package) in &config.packages {
+        let Some(source) = &package.source else {
+            continue;
+        };
+
+        let checkout_dir = cache_directory.join(REMOTE_SOURCES_DIR).join(name);
+        let commit = sync_one(name, source, &checkout_dir, cache, update)
+            .with_context(|| format!("sync remote source for package {:?}", name))?;
+        cache.remote_commits.insert(name.clone(), commit);
+        cache.remote_rev.insert(name.clone(), source.rev.clone());
+
+        let root = match &source.subdirectory {
+            Some(subdirectory) => checkout_dir.join(subdirectory),
+            None => checkout_dir,
+        };
+        roots.insert(name.clone(), root);
+    }
+
+    Ok(roots)
+}
+
+fn sync_one(
+    name: &str,
+    source: &RemoteSource,
+    checkout_dir: &Path,
+    cache: &Cache,
+    update: bool,
+) -> Result<String> {
+    let cached_rev = cache.remote_rev.get(name);
+    if checkout_dir.is_dir()
+        && !update
+        && cached_rev.is_some_and(|cached| cached == &source.rev)
+    {
+        if let Some(commit) = cache.remote_commits.get(name) {
+            debug!(
+                "Remote source for package {:?} is pinned to {:?} already, re-checking out {} instead of fetching. Use --update to refresh.",
+                name, source.rev, commit
+            );
+            run_git(checkout_dir, &["checkout", commit])
+                .context("check out cached pinned commit")?;
+            return Ok(commit.clone());
+        }
+    }
+
+    if let Some(parent) = checkout_dir.parent() {
+        std::fs::create_dir_all(parent).context("create remote sources directory")?;
+    }
+    if checkout_dir.is_dir() {
+        std::fs::remove_dir_all(checkout_dir).context("remove stale remote checkout")?;
+    }
+
+    let checkout_dir_str = checkout_dir
+        .to_str()
+        .context("remote checkout path is not valid UTF-8")?;
+
+    debug!(
+        "Cloning {:?} (rev {:?}) for package {:?}",
+        source.git, source.rev, name
+    );
+    let cloned = match &source.rev {
+        // No rev configured: shallow-clone the remote's default branch tip.
+        None => run_git(
+            Path::new("."),
+            &["clone", "--depth", "1", &source.git, checkout_dir_str],
+        ),
+        // A branch or tag name can be shallow-cloned directly.
+        Some(rev) => run_git(
+            Path::new("."),
+            &[
+                "clone",
+                "--depth",
+                "1",
+                "--branch",
+                rev,
+                &source.git,
+                checkout_dir_str,
+            ],
+        ),
+    };
+    if cloned.is_err() {
+        // `rev` may be a commit hash, which `--branch` can't shallow-clone directly; fall back
+        // to a full clone and check it out by hash.
+        run_git(Path::new("."), &["clone", &source.git, checkout_dir_str])
+            .context("clone remote package source")?;
+        if let Some(rev) = &source.rev {
+            run_git(checkout_dir, &["checkout", rev]).context("check out pinned rev")?;
+        }
+    }
+
+    let commit = run_git(checkout_dir, &["rev-parse", "HEAD"])
+        .context("resolve checked-out commit")?
+        .trim()
+        .to_string();
+    Ok(commit)
+}
+
+fn run_git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .context("run git")?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}